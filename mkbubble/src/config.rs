@@ -0,0 +1,66 @@
+//! Config for the streaming daemon: a TOML file with CLI overrides layered
+//! on top, same idea as `AnalyzeArgs` for the one-shot CLI path but with more
+//! fields than are reasonable to pass as flags alone.
+
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StreamConfig {
+    pub redis_url: Option<String>,
+    pub framerate: Option<f64>,
+    pub channel: Option<String>,
+    pub debug: Option<bool>,
+}
+
+impl StreamConfig {
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&text)?)
+    }
+
+    /// CLI flags always win over whatever the TOML file set.
+    pub fn apply_overrides(&mut self, overrides: CliOverrides) {
+        if let Some(v) = overrides.redis_url {
+            self.redis_url = Some(v);
+        }
+        if let Some(v) = overrides.framerate {
+            self.framerate = Some(v);
+        }
+        if let Some(v) = overrides.channel {
+            self.channel = Some(v);
+        }
+        if overrides.debug {
+            self.debug = Some(true);
+        }
+    }
+
+    pub fn resolve(self) -> Result<ResolvedStreamConfig, Box<dyn std::error::Error>> {
+        Ok(ResolvedStreamConfig {
+            redis_url: self
+                .redis_url
+                .ok_or("missing redis_url: set it in the config file or pass --redis-url")?,
+            framerate: self.framerate.unwrap_or(30.0),
+            channel: self.channel.unwrap_or_else(|| "mkbubble".to_string()),
+            debug: self.debug.unwrap_or(false),
+        })
+    }
+}
+
+/// CLI flags that can override whatever the TOML config file says.
+#[derive(Debug, Clone, Default)]
+pub struct CliOverrides {
+    pub redis_url: Option<String>,
+    pub framerate: Option<f64>,
+    pub channel: Option<String>,
+    pub debug: bool,
+}
+
+/// A fully resolved config, ready for `stream::run`.
+#[derive(Debug, Clone)]
+pub struct ResolvedStreamConfig {
+    pub redis_url: String,
+    pub framerate: f64,
+    pub channel: String,
+    pub debug: bool,
+}
@@ -0,0 +1,42 @@
+//! Conversions between the `image` crate's `DynamicImage` and OpenCV's
+//! `Mat`, shared by anything that hands frames back and forth between the
+//! two (`stream`'s video frame source, `rectify`'s perspective warp).
+
+use image::{DynamicImage, GenericImageView, ImageBuffer, RgbImage};
+use opencv::core::{Mat, MatTrait, MatTraitConst, Scalar, CV_8UC3};
+
+/// Converts a `CV_8UC3` BGR frame (as produced by `VideoCapture::read` or
+/// `imgproc::warp_perspective`) into an `image` crate `DynamicImage`.
+pub(crate) fn mat_to_image(mat: &Mat) -> opencv::Result<DynamicImage> {
+    let width = mat.cols() as u32;
+    let height = mat.rows() as u32;
+    let bgr = mat.data_bytes()?;
+
+    let mut rgb = vec![0u8; bgr.len()];
+    for (src, dst) in bgr.chunks_exact(3).zip(rgb.chunks_exact_mut(3)) {
+        dst[0] = src[2];
+        dst[1] = src[1];
+        dst[2] = src[0];
+    }
+
+    let buf: RgbImage =
+        ImageBuffer::from_raw(width, height, rgb).expect("mat dimensions match buffer length");
+    Ok(DynamicImage::ImageRgb8(buf))
+}
+
+/// Converts an `image` crate `DynamicImage` into a `CV_8UC3` BGR `Mat`, the
+/// inverse of `mat_to_image`.
+pub(crate) fn image_to_mat(img: &DynamicImage) -> opencv::Result<Mat> {
+    let rgb = img.to_rgb8();
+    let (width, height) = rgb.dimensions();
+    let mut bgr = vec![0u8; rgb.as_raw().len()];
+    for (src, dst) in rgb.as_raw().chunks_exact(3).zip(bgr.chunks_exact_mut(3)) {
+        dst[0] = src[2];
+        dst[1] = src[1];
+        dst[2] = src[0];
+    }
+    let mut mat =
+        Mat::new_rows_cols_with_default(height as i32, width as i32, CV_8UC3, Scalar::all(0.0))?;
+    mat.data_bytes_mut()?.copy_from_slice(&bgr);
+    Ok(mat)
+}
@@ -0,0 +1,176 @@
+//! Turns a source frame into the `Mask` the EDT packing loop consumes.
+//! `--mask` selects which strategy classifies foreground pixels; all of them
+//! bottom out in the same dense bitset so `packing::pack_circles` never has
+//! to know which one ran.
+
+use crate::packing::{calculate_luminance, Mask, Pixel};
+use image::{DynamicImage, GenericImageView};
+use opencv::{
+    core::{Mat, MatTrait, MatTraitConst, Point, Scalar, Vector, CV_8UC1},
+    imgproc,
+};
+use rayon::prelude::*;
+
+#[derive(Clone, Copy, Debug)]
+pub enum MaskMode {
+    /// The original fixed `luminance > 0.5` threshold.
+    Luminance,
+    /// A single global threshold picked automatically from the luminance
+    /// histogram (`imgproc::THRESH_OTSU`), for frames with even lighting but
+    /// an unpredictable brightness level.
+    Otsu,
+    /// A locally-varying threshold (`imgproc::adaptive_threshold`), for
+    /// frames with uneven illumination across the frame.
+    Adaptive,
+    /// Otsu-threshold, then keep only connected components whose area is at
+    /// least `min_contour_area`, so speckle noise doesn't spawn tiny
+    /// near-instantly-rejected seeds.
+    Contours,
+}
+
+/// Builds the `Mask` the EDT loop packs circles into, via `mode`.
+/// `min_contour_area` only matters for `MaskMode::Contours`.
+pub fn build_mask(
+    img: &DynamicImage,
+    mode: MaskMode,
+    min_contour_area: f64,
+) -> opencv::Result<Mask> {
+    match mode {
+        MaskMode::Luminance => Ok(luminance_mask(img)),
+        MaskMode::Otsu => otsu_mask(img),
+        MaskMode::Adaptive => adaptive_mask(img),
+        MaskMode::Contours => contour_mask(img, min_contour_area),
+    }
+}
+
+fn luminance_mask(img: &DynamicImage) -> Mask {
+    let (width, height) = img.dimensions();
+    let pixels: Vec<Pixel> = (0..width * height)
+        .into_par_iter()
+        .filter_map(|i| {
+            let x = i % width;
+            let y = i / width;
+            let p = img.get_pixel(x, y).0;
+            if calculate_luminance(p[0], p[1], p[2]) > 0.5 {
+                Some(Pixel { x, y })
+            } else {
+                None
+            }
+        })
+        .collect();
+    Mask::from_pixels(width, height, pixels)
+}
+
+/// Renders `img`'s luminance into an 8-bit grayscale `Mat`, the common input
+/// the OpenCV-backed strategies threshold. Also reused by `rectify` to find
+/// the target region's quad before any masking strategy runs. The luminance
+/// pass runs over rayon like `luminance_mask`, then is copied into the `Mat`
+/// in one bulk write instead of one `at_2d_mut` FFI call per pixel.
+pub(crate) fn luminance_mat(img: &DynamicImage) -> opencv::Result<Mat> {
+    let (width, height) = img.dimensions();
+    let rgb = img.to_rgb8();
+    let gray: Vec<u8> = rgb
+        .as_raw()
+        .par_chunks_exact(3)
+        .map(|px| (calculate_luminance(px[0], px[1], px[2]) * 255.0).round() as u8)
+        .collect();
+    let mut mat =
+        Mat::new_rows_cols_with_default(height as i32, width as i32, CV_8UC1, Scalar::all(0.0))?;
+    mat.data_bytes_mut()?.copy_from_slice(&gray);
+    Ok(mat)
+}
+
+/// Collects every non-zero pixel of a binary `Mat` into a `Mask`, in the same
+/// rayon-over-a-bulk-read style as `luminance_mask` instead of probing the
+/// `Mat` one `at_2d` FFI call per pixel.
+fn mat_to_mask(binary: &Mat, width: u32, height: u32) -> opencv::Result<Mask> {
+    let bytes = binary.data_bytes()?;
+    let pixels: Vec<Pixel> = bytes
+        .par_iter()
+        .enumerate()
+        .filter_map(|(i, &v)| {
+            if v > 0 {
+                Some(Pixel {
+                    x: i as u32 % width,
+                    y: i as u32 / width,
+                })
+            } else {
+                None
+            }
+        })
+        .collect();
+    Ok(Mask::from_pixels(width, height, pixels))
+}
+
+fn otsu_mask(img: &DynamicImage) -> opencv::Result<Mask> {
+    let (width, height) = img.dimensions();
+    let gray = luminance_mat(img)?;
+    let mut binary = Mat::default();
+    imgproc::threshold(
+        &gray,
+        &mut binary,
+        0.0,
+        255.0,
+        imgproc::THRESH_BINARY + imgproc::THRESH_OTSU,
+    )?;
+    mat_to_mask(&binary, width, height)
+}
+
+fn adaptive_mask(img: &DynamicImage) -> opencv::Result<Mask> {
+    let (width, height) = img.dimensions();
+    let gray = luminance_mat(img)?;
+    let mut binary = Mat::default();
+    imgproc::adaptive_threshold(
+        &gray,
+        &mut binary,
+        255.0,
+        imgproc::ADAPTIVE_THRESH_GAUSSIAN_C,
+        imgproc::THRESH_BINARY,
+        51,
+        -5.0,
+    )?;
+    mat_to_mask(&binary, width, height)
+}
+
+fn contour_mask(img: &DynamicImage, min_area: f64) -> opencv::Result<Mask> {
+    let (width, height) = img.dimensions();
+    let gray = luminance_mat(img)?;
+    let mut binary = Mat::default();
+    imgproc::threshold(
+        &gray,
+        &mut binary,
+        0.0,
+        255.0,
+        imgproc::THRESH_BINARY + imgproc::THRESH_OTSU,
+    )?;
+
+    let mut contours = Vector::<Vector<Point>>::new();
+    imgproc::find_contours(
+        &binary,
+        &mut contours,
+        imgproc::RETR_EXTERNAL,
+        imgproc::CHAIN_APPROX_SIMPLE,
+        Point::new(0, 0),
+    )?;
+
+    let mut kept =
+        Mat::new_rows_cols_with_default(height as i32, width as i32, CV_8UC1, Scalar::all(0.0))?;
+    for contour in contours.iter() {
+        if imgproc::contour_area(&contour, false)? < min_area {
+            continue;
+        }
+        let solo = Vector::<Vector<Point>>::from_iter([contour]);
+        imgproc::draw_contours(
+            &mut kept,
+            &solo,
+            0,
+            Scalar::all(255.0),
+            -1,
+            imgproc::LINE_8,
+            &opencv::core::no_array(),
+            i32::MAX,
+            Point::new(0, 0),
+        )?;
+    }
+    mat_to_mask(&kept, width, height)
+}
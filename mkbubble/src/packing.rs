@@ -0,0 +1,448 @@
+//! The circle-packing core: turn a bright-pixel mask into a `Vec<Circle>`.
+//! Kept independent of how frames arrive (single file, directory, video,
+//! Redis) so both the one-shot CLI path and the streaming daemon share it.
+
+use image::{ImageBuffer, Rgb, RgbImage};
+use opencv::{
+    core::{Mat, MatTrait, MatTraitConst, Rect, Scalar, CV_32F, CV_8UC1},
+    imgproc,
+};
+use rayon::prelude::*;
+use std::{cmp::Ordering, collections::BTreeSet, fmt::Display};
+
+const MAX_RADIUS: u32 = 25;
+
+#[derive(Clone, Debug, Hash)]
+pub(crate) struct Pixel {
+    pub(crate) x: u32,
+    pub(crate) y: u32,
+}
+
+impl PartialEq for Pixel {
+    fn eq(&self, other: &Self) -> bool {
+        self.x == other.x && self.y == other.y
+    }
+}
+impl PartialOrd for Pixel {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        match self.x.partial_cmp(&other.x) {
+            Some(std::cmp::Ordering::Equal) => self.y.partial_cmp(&other.y),
+            other => other,
+        }
+    }
+}
+impl Eq for Pixel {}
+impl Ord for Pixel {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        match self.x.cmp(&other.x) {
+            std::cmp::Ordering::Equal => self.y.cmp(&other.y),
+            other => other,
+        }
+    }
+}
+
+/// Dense bitset over the whole frame, indexed by `y * width + x`. Membership
+/// checks against it are a single array read instead of hashing into an
+/// `IndexSet`, which matters here since `check_circle` probes it for every
+/// pixel of every candidate radius.
+pub(crate) struct Mask {
+    bits: Vec<bool>,
+    width: u32,
+    height: u32,
+    len: usize,
+}
+
+impl Mask {
+    pub(crate) fn from_pixels(
+        width: u32,
+        height: u32,
+        pixels: impl IntoIterator<Item = Pixel>,
+    ) -> Self {
+        let mut bits = vec![false; (width * height) as usize];
+        let mut len = 0;
+        for px in pixels {
+            let idx = (px.y * width + px.x) as usize;
+            if !bits[idx] {
+                bits[idx] = true;
+                len += 1;
+            }
+        }
+        Mask {
+            bits,
+            width,
+            height,
+            len,
+        }
+    }
+
+    pub(crate) fn contains(&self, px: &Pixel) -> bool {
+        if px.x >= self.width || px.y >= self.height {
+            return false;
+        }
+        self.bits[(px.y * self.width + px.x) as usize]
+    }
+
+    pub(crate) fn remove(&mut self, px: &Pixel) {
+        if px.x >= self.width || px.y >= self.height {
+            return;
+        }
+        let idx = (px.y * self.width + px.x) as usize;
+        if self.bits[idx] {
+            self.bits[idx] = false;
+            self.len -= 1;
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = Pixel> + '_ {
+        let width = self.width;
+        self.bits
+            .iter()
+            .enumerate()
+            .filter(|(_, set)| **set)
+            .map(move |(idx, _)| Pixel {
+                x: idx as u32 % width,
+                y: idx as u32 / width,
+            })
+    }
+}
+
+/// A candidate seed for the next circle, keyed by its EDT distance so the
+/// max-distance candidate can be popped without a full `min_max_loc` scan.
+/// `dist_bits` stores the raw bit pattern of a non-negative `f32`, which
+/// happens to sort the same way as the float itself.
+#[derive(Clone, Debug)]
+struct Candidate {
+    dist_bits: u32,
+    pixel: Pixel,
+}
+
+impl Candidate {
+    fn new(dist: f32, pixel: Pixel) -> Self {
+        Candidate {
+            dist_bits: dist.max(0.0).to_bits(),
+            pixel,
+        }
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.dist_bits == other.dist_bits && self.pixel == other.pixel
+    }
+}
+impl Eq for Candidate {}
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match self.dist_bits.cmp(&other.dist_bits) {
+            Ordering::Equal => self.pixel.cmp(&other.pixel),
+            ord => ord,
+        }
+    }
+}
+
+/// A packed circle, in image pixel space (origin top-left).
+#[derive(Debug, Clone)]
+pub struct Circle {
+    pub x: u32,
+    pub y: u32,
+    pub r: u32,
+}
+impl Display for Circle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("[{},{},{}]", self.x, self.y, self.r))
+    }
+}
+
+/// Holds the Euclidean distance transform across the whole packing run so it
+/// only ever gets touched on the cropped region a newly-accepted circle can
+/// affect, instead of being rebuilt from scratch on every iteration.
+struct EdtState {
+    binary: Mat,
+    dist: Mat,
+    width: i32,
+    height: i32,
+    candidates: BTreeSet<Candidate>,
+}
+
+impl EdtState {
+    /// Builds the initial binary mask and runs one full distance transform;
+    /// every iteration after this only recomputes a cropped ROI.
+    fn new(mask: &Mask, width: i32, height: i32) -> opencv::Result<Self> {
+        let mut binary = Mat::new_rows_cols_with_default(height, width, CV_8UC1, Scalar::all(0.0))?;
+        for px in mask.iter() {
+            *binary.at_2d_mut::<u8>(px.y as i32, px.x as i32)? = 255;
+        }
+        let mut dist = Mat::default();
+        imgproc::distance_transform(
+            &binary,
+            &mut dist,
+            imgproc::DIST_L2,
+            imgproc::DIST_MASK_PRECISE,
+            CV_32F,
+        )?;
+
+        let mut state = EdtState {
+            binary,
+            dist,
+            width,
+            height,
+            candidates: BTreeSet::new(),
+        };
+        state.reseed_candidates(mask)?;
+        Ok(state)
+    }
+
+    /// Scans the whole `dist` Mat once and records every masked pixel as a
+    /// candidate. Only called on the initial build, never per-iteration.
+    fn reseed_candidates(&mut self, mask: &Mask) -> opencv::Result<()> {
+        for px in mask.iter() {
+            let d = *self.dist.at_2d::<f32>(px.y as i32, px.x as i32)?;
+            if d > 0.0 {
+                self.candidates.insert(Candidate::new(d, px.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Clears `to_clear` from the binary mask, then recomputes the distance
+    /// transform only on the bounding box of `to_clear` expanded by
+    /// `MAX_RADIUS` (clamped to the image bounds), writing the result back
+    /// into `dist`'s ROI. Stale candidates inside that ROI are dropped and
+    /// replaced with freshly computed ones.
+    fn update_region(
+        &mut self,
+        to_clear: &[Pixel],
+        mask: &Mask,
+        cx: i32,
+        cy: i32,
+        r: i32,
+    ) -> opencv::Result<()> {
+        for px in to_clear {
+            *self.binary.at_2d_mut::<u8>(px.y as i32, px.x as i32)? = 0;
+        }
+
+        let pad = r + MAX_RADIUS as i32;
+        let x0 = (cx - pad).max(0);
+        let y0 = (cy - pad).max(0);
+        let x1 = (cx + pad + 1).min(self.width);
+        let y1 = (cy + pad + 1).min(self.height);
+        if x1 <= x0 || y1 <= y0 {
+            return Ok(());
+        }
+        let roi = Rect::new(x0, y0, x1 - x0, y1 - y0);
+
+        let binary_roi = Mat::roi(&self.binary, roi)?;
+        let mut roi_dist = Mat::default();
+        imgproc::distance_transform(
+            &binary_roi,
+            &mut roi_dist,
+            imgproc::DIST_L2,
+            imgproc::DIST_MASK_PRECISE,
+            CV_32F,
+        )?;
+        // `Mat::roi` has no mutable counterpart, but the Mat it returns
+        // shares the same underlying buffer as `self.dist` (per its own doc
+        // comment), so copying into it writes straight back into `dist`.
+        let mut dist_roi = Mat::roi(&self.dist, roi)?;
+        roi_dist.copy_to(&mut dist_roi)?;
+
+        // Drop every stale candidate whose pixel falls inside the ROI, then
+        // reinsert the ones still present in the mask with their fresh
+        // distance value.
+        self.candidates.retain(|c| {
+            let px = c.pixel.x as i32;
+            let py = c.pixel.y as i32;
+            !(px >= x0 && px < x1 && py >= y0 && py < y1)
+        });
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let px = Pixel {
+                    x: x as u32,
+                    y: y as u32,
+                };
+                if !mask.contains(&px) {
+                    continue;
+                }
+                let d = *self.dist.at_2d::<f32>(y, x)?;
+                if d > 0.0 {
+                    self.candidates.insert(Candidate::new(d, px));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Pops the highest-distance candidate, lazily discarding any that no
+    /// longer belong to `mask` (their region was cleared by an earlier
+    /// circle but the candidate entry hadn't been invalidated yet).
+    fn pop_best(&mut self, mask: &Mask) -> Option<Pixel> {
+        while let Some(best) = self.candidates.pop_last() {
+            if mask.contains(&best.pixel) {
+                return Some(best.pixel);
+            }
+        }
+        None
+    }
+}
+
+/// Packs circles into an already-classified `mask` (built by one of
+/// `masking`'s strategies). Returns the packed circles in image pixel space
+/// (origin top-left); callers that emit ECharts coordinates still need to
+/// flip `y`. Rendering the circles themselves (e.g.
+/// `fist_pass.png`/`output.png`) is the caller's job, via
+/// `render::render_preview` — this function only knows about the mask, not
+/// how a circle should be drawn.
+pub fn pack_circles(width: u32, height: u32, mut mask: Mask) -> opencv::Result<Vec<Circle>> {
+    let mut circles: Vec<Circle> = Vec::new();
+    let mut edt = EdtState::new(&mask, width as i32, height as i32)?;
+
+    while mask.len() > 0 {
+        if let Some(max_point) = edt.pop_best(&mask) {
+            let circle = find_biggest_circle(&mask, max_point.x, max_point.y, MAX_RADIUS);
+            if circle.r < 3 {
+                let px = Pixel {
+                    x: max_point.x,
+                    y: max_point.y,
+                };
+                mask.remove(&px);
+                edt.update_region(&[px], &mask, max_point.x as i32, max_point.y as i32, 0)?;
+                continue;
+            }
+            circles.push(circle.clone());
+
+            // Remove the pixels of this new circle from the mask and repeat
+            let to_rm = pixels_in_circle(circle.x, circle.y, circle.r);
+            for px in &to_rm {
+                mask.remove(px);
+            }
+            edt.update_region(
+                &to_rm,
+                &mask,
+                circle.x as i32,
+                circle.y as i32,
+                circle.r as i32,
+            )?;
+        } else {
+            break;
+        }
+    }
+
+    Ok(circles)
+}
+
+/// Formats circles as an ECharts-ready `[[x,y,r],...]` array, flipping `y`
+/// since in ECharts `y = 0` is at the bottom of the frame.
+pub fn to_echart_array(circles: &[Circle], height: u32) -> String {
+    let circle_fmt = circles
+        .iter()
+        .map(|c| Circle {
+            x: c.x,
+            y: height - c.y,
+            r: c.r,
+        })
+        .map(|c| c.to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+    format!("[{}]", circle_fmt)
+}
+
+fn find_biggest_circle(valid_px: &Mask, cx: u32, cy: u32, max_radius: u32) -> Circle {
+    // Circles are nested: if radius r is fully inside valid_px, every radius
+    // below it is too, so each candidate radius can be validated
+    // independently and the winner picked by a parallel reduction instead of
+    // growing the radius one step at a time.
+    let r = (3..=max_radius)
+        .into_par_iter()
+        .filter(|&r| check_circle(valid_px, cx, cy, r))
+        .max()
+        .unwrap_or(1);
+    Circle { x: cx, y: cy, r }
+}
+
+fn pixels_in_circle(cx: u32, cy: u32, r: u32) -> Vec<Pixel> {
+    let mut pixels = Vec::new();
+    let cx = cx as i32;
+    let cy = cy as i32;
+    let radius = r as i32;
+    for dy in -radius..=radius {
+        for dx in -radius..=radius {
+            if dx * dx + dy * dy <= radius * radius {
+                let x = cx + dx;
+                let y = cy + dy;
+                if x < 0 || y < 0 {
+                    // We ignore out of bound pixels
+                    continue;
+                }
+                pixels.push(Pixel {
+                    x: x as u32,
+                    y: y as u32,
+                });
+            }
+        }
+    }
+    pixels
+}
+
+fn check_circle(valid_px: &Mask, cx: u32, cy: u32, radius: u32) -> bool {
+    let cx = cx as i32;
+    let cy = cy as i32;
+    let radius = radius as i32;
+    // find_biggest_circle already parallelizes over candidate radii, and
+    // most calls here cover only a handful of rows; a second layer of rayon
+    // spawn/join on top of that costs more than it saves, so this loop
+    // stays serial.
+    (-radius..=radius).all(|dy| {
+        (-radius..=radius).all(|dx| {
+            if dx * dx + dy * dy > radius * radius {
+                return true;
+            }
+            let x = cx + dx;
+            let y = cy + dy;
+            if x < 0 || y < 0 {
+                // We ignore out of bound pixels
+                return true;
+            }
+            let px = Pixel {
+                x: x as u32,
+                y: y as u32,
+            };
+            valid_px.contains(&px)
+        })
+    })
+}
+
+pub(crate) fn calculate_luminance(r: u8, g: u8, b: u8) -> f32 {
+    // Using the standard luminance formula: Y = 0.299*R + 0.587*G + 0.114*B
+    let r_norm = r as f32 / 255.0;
+    let g_norm = g as f32 / 255.0;
+    let b_norm = b as f32 / 255.0;
+    0.299 * r_norm + 0.587 * g_norm + 0.114 * b_norm
+}
+
+pub(crate) fn debug_img(
+    mask: &Mask,
+    width: u32,
+    height: u32,
+    filename: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut img: RgbImage = ImageBuffer::new(width, height);
+    for pixel in img.pixels_mut() {
+        *pixel = Rgb([0, 0, 0]); // Black color
+    }
+    for p in mask.iter() {
+        if p.x < width && p.y < height {
+            img.put_pixel(p.x, p.y, Rgb([255, 255, 255]));
+        }
+    }
+    img.save(filename)?;
+    Ok(())
+}
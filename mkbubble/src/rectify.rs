@@ -0,0 +1,205 @@
+//! Optional perspective correction for keystoned frames (e.g. a projector or
+//! an off-axis camera): detect the target region's quadrilateral, warp it to
+//! an upright rectangle before masking runs, then map packed circles back
+//! into the original frame's coordinate space so emitted ECharts coordinates
+//! are unaffected by the correction.
+
+use crate::convert::{image_to_mat, mat_to_image};
+use crate::masking;
+use crate::packing::Circle;
+use image::{DynamicImage, GenericImageView};
+use opencv::{
+    core::{Mat, MatTraitConst, Point, Point2f, Scalar, Size, Vector},
+    imgproc,
+};
+use std::error::Error;
+
+/// A rectified frame, plus the inverse homography needed to map circles
+/// packed into it back into the original frame's space.
+pub struct Rectified {
+    pub image: DynamicImage,
+    /// Row-major 3x3 inverse perspective matrix (rectified -> original).
+    inverse: [f64; 9],
+}
+
+impl Rectified {
+    /// Maps `circles` packed in the rectified frame back into the original
+    /// `source_width`x`source_height` frame. A circle's radius is rescaled
+    /// by how much the inverse homography stretches lengths near its
+    /// center, sampled along the x axis; this is an approximation away from
+    /// pure similarity transforms, but keystone correction is close enough
+    /// to one for the packed radii to stay visually consistent. Circles
+    /// whose center maps outside the original frame (possible for centers
+    /// in the `--rectify-margin` padding) are dropped, since they have no
+    /// corresponding pixel to land on and would otherwise underflow
+    /// `to_echart_array`'s `height - y`.
+    pub fn unrectify_circles(
+        &self,
+        circles: &[Circle],
+        source_width: u32,
+        source_height: u32,
+    ) -> Vec<Circle> {
+        circles
+            .iter()
+            .filter_map(|c| {
+                let (cx, cy) = self.apply(c.x as f64, c.y as f64);
+                if cx < 0.0 || cy < 0.0 || cx >= source_width as f64 || cy >= source_height as f64 {
+                    return None;
+                }
+                let (ex, ey) = self.apply((c.x + c.r) as f64, c.y as f64);
+                let r = ((ex - cx).powi(2) + (ey - cy).powi(2)).sqrt();
+                Some(Circle {
+                    x: cx.round() as u32,
+                    y: cy.round() as u32,
+                    r: r.round().max(1.0) as u32,
+                })
+            })
+            .collect()
+    }
+
+    fn apply(&self, x: f64, y: f64) -> (f64, f64) {
+        let m = self.inverse;
+        let w = m[6] * x + m[7] * y + m[8];
+        (
+            (m[0] * x + m[1] * y + m[2]) / w,
+            (m[3] * x + m[4] * y + m[5]) / w,
+        )
+    }
+}
+
+/// Detects the largest bright quadrilateral in `img`, warps it to a
+/// rectangle padded by `margin` pixels on every side, and returns the
+/// rectified frame. When `debug` is set, saves `quad.png` (the detected
+/// corners drawn over the source frame) and `warped.png` (the rectified
+/// frame). Errors if no quadrilateral can be found.
+pub fn rectify(img: &DynamicImage, margin: f64, debug: bool) -> Result<Rectified, Box<dyn Error>> {
+    let (width, height) = img.dimensions();
+
+    let gray = masking::luminance_mat(img)?;
+    let mut binary = Mat::default();
+    imgproc::threshold(
+        &gray,
+        &mut binary,
+        0.0,
+        255.0,
+        imgproc::THRESH_BINARY + imgproc::THRESH_OTSU,
+    )?;
+
+    let mut contours = Vector::<Vector<Point>>::new();
+    imgproc::find_contours(
+        &binary,
+        &mut contours,
+        imgproc::RETR_EXTERNAL,
+        imgproc::CHAIN_APPROX_SIMPLE,
+        Point::new(0, 0),
+    )?;
+    let largest = contours
+        .iter()
+        .max_by(|a, b| {
+            let area_a = imgproc::contour_area(a, false).unwrap_or(0.0);
+            let area_b = imgproc::contour_area(b, false).unwrap_or(0.0);
+            area_a.total_cmp(&area_b)
+        })
+        .ok_or("no target region found to rectify")?;
+
+    let perimeter = imgproc::arc_length(&largest, true)?;
+    let mut approx = Vector::<Point>::new();
+    imgproc::approx_poly_dp(&largest, &mut approx, 0.02 * perimeter, true)?;
+    if approx.len() != 4 {
+        return Err(format!(
+            "detected target region has {} corners, expected a quadrilateral (4)",
+            approx.len()
+        )
+        .into());
+    }
+    let corners = order_corners(&approx);
+
+    let dst_width = width as f64 + 2.0 * margin;
+    let dst_height = height as f64 + 2.0 * margin;
+    let mut src_pts = Vector::<Point2f>::new();
+    for (x, y) in corners {
+        src_pts.push(Point2f::new(x as f32, y as f32));
+    }
+    let mut dst_pts = Vector::<Point2f>::new();
+    dst_pts.push(Point2f::new(margin as f32, margin as f32));
+    dst_pts.push(Point2f::new((margin + width as f64) as f32, margin as f32));
+    dst_pts.push(Point2f::new(
+        (margin + width as f64) as f32,
+        (margin + height as f64) as f32,
+    ));
+    dst_pts.push(Point2f::new(margin as f32, (margin + height as f64) as f32));
+
+    let forward = imgproc::get_perspective_transform(&src_pts, &dst_pts, opencv::core::DECOMP_LU)?;
+
+    let source_mat = image_to_mat(img)?;
+    let mut warped = Mat::default();
+    imgproc::warp_perspective(
+        &source_mat,
+        &mut warped,
+        &forward,
+        Size::new(dst_width.round() as i32, dst_height.round() as i32),
+        imgproc::INTER_LINEAR,
+        opencv::core::BORDER_CONSTANT,
+        Scalar::all(0.0),
+    )?;
+    let rectified_image = mat_to_image(&warped)?;
+
+    let mut inverse_mat = Mat::default();
+    opencv::core::invert(&forward, &mut inverse_mat, opencv::core::DECOMP_LU)?;
+    let mut inverse = [0f64; 9];
+    for row in 0..3 {
+        for col in 0..3 {
+            inverse[row * 3 + col] = *inverse_mat.at_2d::<f64>(row as i32, col as i32)?;
+        }
+    }
+
+    if debug {
+        let mut overlay = source_mat.clone();
+        let quad: Vector<Vector<Point>> = Vector::from_iter([Vector::from_iter(
+            corners
+                .iter()
+                .map(|&(x, y)| Point::new(x.round() as i32, y.round() as i32)),
+        )]);
+        imgproc::polylines(
+            &mut overlay,
+            &quad,
+            true,
+            Scalar::new(0.0, 0.0, 255.0, 0.0),
+            3,
+            imgproc::LINE_8,
+            0,
+        )?;
+        mat_to_image(&overlay)?.save("quad.png")?;
+        rectified_image.save("warped.png")?;
+    }
+
+    Ok(Rectified {
+        image: rectified_image,
+        inverse,
+    })
+}
+
+/// Orders 4 arbitrary corner points as (top-left, top-right, bottom-right,
+/// bottom-left), using the classic sum/difference trick: top-left has the
+/// smallest `x+y`, bottom-right the largest; top-right has the smallest
+/// `y-x`, bottom-left the largest.
+fn order_corners(points: &Vector<Point>) -> [(f64, f64); 4] {
+    let pts: Vec<(f64, f64)> = points.iter().map(|p| (p.x as f64, p.y as f64)).collect();
+    let tl = *pts
+        .iter()
+        .min_by(|a, b| (a.0 + a.1).total_cmp(&(b.0 + b.1)))
+        .unwrap();
+    let br = *pts
+        .iter()
+        .max_by(|a, b| (a.0 + a.1).total_cmp(&(b.0 + b.1)))
+        .unwrap();
+    let tr = *pts
+        .iter()
+        .min_by(|a, b| (a.1 - a.0).total_cmp(&(b.1 - b.0)))
+        .unwrap();
+    let bl = *pts
+        .iter()
+        .max_by(|a, b| (a.1 - a.0).total_cmp(&(b.1 - b.0)))
+        .unwrap();
+    [tl, tr, br, bl]
+}
@@ -0,0 +1,125 @@
+//! A small drawing backend for circle-packing debug/preview output. Unlike
+//! `packing`'s internal `debug_img`, which rasterizes pixel sets, this
+//! renders the `Vec<Circle>` directly as anti-aliased outlines and
+//! translucent fills over a copy of the source frame, so a circle keeps its
+//! identity instead of being flattened into a binary mask.
+
+use crate::packing::Circle;
+use image::{DynamicImage, Rgba, RgbaImage};
+use std::path::Path;
+
+pub type Color = Rgba<u8>;
+
+/// A small fixed palette so adjacent circles in `circles.iter().enumerate()`
+/// render in visibly different colors.
+const PALETTE: [Color; 6] = [
+    Rgba([230, 25, 75, 255]),
+    Rgba([60, 180, 75, 255]),
+    Rgba([255, 225, 25, 255]),
+    Rgba([0, 130, 200, 255]),
+    Rgba([245, 130, 48, 255]),
+    Rgba([145, 30, 180, 255]),
+];
+
+pub fn palette_color(index: usize) -> Color {
+    PALETTE[index % PALETTE.len()]
+}
+
+/// The canvas circle primitives blend into, via simple alpha compositing.
+pub struct Canvas {
+    img: RgbaImage,
+}
+
+impl Canvas {
+    /// Starts from a copy of `source`, so the packing can be visually QC'd
+    /// against the frame that produced it.
+    pub fn from_source(source: &DynamicImage) -> Self {
+        Canvas {
+            img: source.to_rgba8(),
+        }
+    }
+
+    /// Alpha-blends `source` over the current canvas contents.
+    pub fn overlay(&mut self, source: &DynamicImage, alpha: f32) {
+        let overlay = source.to_rgba8();
+        let alpha = alpha.clamp(0.0, 1.0);
+        for (dst, src) in self.img.pixels_mut().zip(overlay.pixels()) {
+            *dst = blend(*dst, Rgba([src[0], src[1], src[2], 255]), alpha);
+        }
+    }
+
+    /// Draws an anti-aliased outline of `circle`, `stroke_width` pixels
+    /// thick. Coverage falls off over roughly one pixel on either side of
+    /// the outline, which is what gives the edge its anti-aliasing.
+    pub fn stroke_circle(&mut self, circle: &Circle, stroke_width: f32, color: Color) {
+        let half = stroke_width / 2.0;
+        self.paint_circle(circle, color, move |dist, r| {
+            (half + 0.5 - (dist - r).abs()).clamp(0.0, 1.0)
+        });
+    }
+
+    /// Draws a translucent filled disc, `alpha` in `0.0..=1.0`.
+    pub fn fill_circle(&mut self, circle: &Circle, alpha: f32, color: Color) {
+        let alpha = alpha.clamp(0.0, 1.0);
+        self.paint_circle(circle, color, move |dist, r| {
+            (r + 0.5 - dist).clamp(0.0, 1.0) * alpha
+        });
+    }
+
+    fn paint_circle(&mut self, circle: &Circle, color: Color, coverage: impl Fn(f32, f32) -> f32) {
+        let (width, height) = self.img.dimensions();
+        let cx = circle.x as f32;
+        let cy = circle.y as f32;
+        let r = circle.r as f32;
+        let pad = r + 2.0;
+        let x0 = (cx - pad).max(0.0) as u32;
+        let y0 = (cy - pad).max(0.0) as u32;
+        let x1 = ((cx + pad).ceil() as u32 + 1).min(width);
+        let y1 = ((cy + pad).ceil() as u32 + 1).min(height);
+
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let dx = x as f32 + 0.5 - cx;
+                let dy = y as f32 + 0.5 - cy;
+                let dist = (dx * dx + dy * dy).sqrt();
+                let coverage = coverage(dist, r);
+                if coverage <= 0.0 {
+                    continue;
+                }
+                let pixel = self.img.get_pixel_mut(x, y);
+                *pixel = blend(*pixel, color, coverage);
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> image::ImageResult<()> {
+        self.img.save(path)
+    }
+}
+
+fn blend(dst: Rgba<u8>, src: Rgba<u8>, coverage: f32) -> Rgba<u8> {
+    let a = coverage.clamp(0.0, 1.0) * (src[3] as f32 / 255.0);
+    let mut out = [0u8; 4];
+    for c in 0..3 {
+        out[c] = (dst[c] as f32 * (1.0 - a) + src[c] as f32 * a).round() as u8;
+    }
+    out[3] = 255;
+    Rgba(out)
+}
+
+/// Renders `circles` as translucent fills with anti-aliased outlines over a
+/// copy of `source`, and saves the composite to `path`.
+pub fn render_preview(
+    source: &DynamicImage,
+    circles: &[Circle],
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut canvas = Canvas::from_source(source);
+    for (i, circle) in circles.iter().enumerate() {
+        let color = palette_color(i);
+        canvas.fill_circle(circle, 0.35, color);
+        canvas.stroke_circle(circle, 1.5, color);
+    }
+    canvas.save(path)?;
+    Ok(())
+}
@@ -0,0 +1,188 @@
+//! Daemon mode: pull frames from a live source at a target framerate, pack
+//! circles into each one with [`packing::pack_circles`], and publish the
+//! resulting ECharts array to Redis.
+
+use crate::config::ResolvedStreamConfig;
+use crate::convert::mat_to_image;
+use crate::masking::{self, MaskMode};
+use crate::packing;
+use crate::render;
+use image::DynamicImage;
+use opencv::core::MatTraitConst;
+use opencv::videoio::{VideoCaptureTrait, VideoCaptureTraitConst};
+use redis::Commands;
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Where live frames come from.
+pub enum FrameSource {
+    /// A directory of numbered frame images, read back in numeric order.
+    FrameDir(PathBuf),
+    /// A video file, decoded frame by frame with OpenCV.
+    Video(PathBuf),
+    /// A Redis pub/sub channel carrying raw (encoded) frame bytes.
+    RedisChannel(String),
+}
+
+/// Pulls frames from `source` at `cfg.framerate`, packs circles into each
+/// one and publishes the resulting `[[x,y,r],...]` array to `cfg.channel` on
+/// `cfg.redis_url`. A frame that arrives while the packer is still behind
+/// schedule is dropped rather than queued. Ctrl-C stops the loop after the
+/// in-flight frame finishes.
+pub fn run(
+    source: FrameSource,
+    cfg: &ResolvedStreamConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = redis::Client::open(cfg.redis_url.as_str())?;
+    let mut publish_conn = client.get_connection()?;
+
+    let running = Arc::new(AtomicBool::new(true));
+    {
+        let running = running.clone();
+        ctrlc::set_handler(move || running.store(false, Ordering::SeqCst))?;
+    }
+
+    let mut frames = FrameReader::open(source, &cfg.redis_url)?;
+    let frame_budget = Duration::from_secs_f64(1.0 / cfg.framerate);
+    let mut next_tick = Instant::now();
+    let mut frame_index: u64 = 0;
+
+    while running.load(Ordering::SeqCst) {
+        let img = match frames.next_frame()? {
+            Some(img) => img,
+            None => break,
+        };
+
+        if Instant::now() > next_tick + frame_budget {
+            eprintln!(
+                "mkbubble: dropped a frame, packer can't keep up at {} fps",
+                cfg.framerate
+            );
+            next_tick = Instant::now();
+            continue;
+        }
+
+        let mask = masking::build_mask(&img, MaskMode::Luminance, 0.0)?;
+        if cfg.debug {
+            packing::debug_img(
+                &mask,
+                img.width(),
+                img.height(),
+                &format!("frame_{frame_index:05}_mask.png"),
+            )?;
+        }
+        let circles = packing::pack_circles(img.width(), img.height(), mask)?;
+        if cfg.debug {
+            render::render_preview(
+                &img,
+                &circles,
+                Path::new(&format!("frame_{frame_index:05}_preview.png")),
+            )?;
+        }
+        eprintln!("mkbubble: packed {} circles", circles.len());
+        let payload = packing::to_echart_array(&circles, img.height());
+        publish_conn.publish::<_, _, ()>(&cfg.channel, payload)?;
+
+        frame_index += 1;
+        next_tick += frame_budget;
+        let remaining = next_tick.saturating_duration_since(Instant::now());
+        if !remaining.is_zero() {
+            std::thread::sleep(remaining);
+        }
+    }
+
+    Ok(())
+}
+
+/// Abstracts over the three supported frame sources behind a single
+/// `next_frame` pull.
+enum FrameReader {
+    Dir {
+        paths: Vec<PathBuf>,
+        next: usize,
+    },
+    Video(opencv::videoio::VideoCapture),
+    /// Subscribing to Redis needs its own connection, held mutably for the
+    /// lifetime of the subscription, so it's driven from a dedicated thread
+    /// that forwards decoded payloads over this channel. The channel is
+    /// bounded to one in-flight frame so a slow consumer drops frames
+    /// instead of letting them pile up in memory.
+    Redis(mpsc::Receiver<Vec<u8>>),
+}
+
+impl FrameReader {
+    fn open(source: FrameSource, redis_url: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        match source {
+            FrameSource::FrameDir(dir) => {
+                let mut paths: Vec<PathBuf> = std::fs::read_dir(&dir)?
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path())
+                    .filter(|path| path.is_file())
+                    .collect();
+                paths.sort_by_key(|path| {
+                    path.file_stem()
+                        .and_then(|stem| stem.to_str())
+                        .and_then(|stem| stem.parse::<u64>().ok())
+                        .unwrap_or(0)
+                });
+                Ok(FrameReader::Dir { paths, next: 0 })
+            }
+            FrameSource::Video(path) => {
+                let path = path.to_str().ok_or("video path must be valid UTF-8")?;
+                let cap = opencv::videoio::VideoCapture::from_file(path, opencv::videoio::CAP_ANY)?;
+                Ok(FrameReader::Video(cap))
+            }
+            FrameSource::RedisChannel(channel) => {
+                let client = redis::Client::open(redis_url)?;
+                let mut conn = client.get_connection()?;
+                let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(1);
+                std::thread::spawn(move || {
+                    let mut pubsub = conn.as_pubsub();
+                    if pubsub.subscribe(&channel).is_err() {
+                        return;
+                    }
+                    while let Ok(msg) = pubsub.get_message() {
+                        if let Ok(payload) = msg.get_payload::<Vec<u8>>() {
+                            // A full channel means the main loop is behind;
+                            // drop this frame instead of blocking the
+                            // subscriber thread on it.
+                            let _ = tx.try_send(payload);
+                        }
+                    }
+                });
+                Ok(FrameReader::Redis(rx))
+            }
+        }
+    }
+
+    fn next_frame(&mut self) -> Result<Option<DynamicImage>, Box<dyn std::error::Error>> {
+        match self {
+            FrameReader::Dir { paths, next } => {
+                if *next >= paths.len() {
+                    return Ok(None);
+                }
+                let path = paths[*next].clone();
+                *next += 1;
+                Ok(Some(image::open(path)?))
+            }
+            FrameReader::Video(cap) => {
+                let mut mat = opencv::core::Mat::default();
+                cap.read(&mut mat)?;
+                if mat.empty() {
+                    return Ok(None);
+                }
+                Ok(Some(mat_to_image(&mat)?))
+            }
+            FrameReader::Redis(rx) => match rx.recv() {
+                Ok(bytes) => Ok(Some(image::load_from_memory(&bytes)?)),
+                Err(_) => Ok(None),
+            },
+        }
+    }
+}